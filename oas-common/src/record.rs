@@ -1,7 +1,13 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "raw_value")]
+use serde_json::value::RawValue;
 use serde_json::Value;
+use serde_with::{serde_as, DeserializeAs, PickFirst, SerializeAs, TimestampSeconds};
 use std::any::Any;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
 use thiserror::Error;
@@ -29,10 +35,44 @@ pub enum DecodingError {
     TypeMismatch(String, String),
     #[error("Deserialization did not return an object")]
     NotAnObject,
+    #[error("Unknown record type: {0}")]
+    UnknownType(String),
+    #[error("JSON patch failed: {0}")]
+    Patch(#[from] json_patch::PatchError),
+}
+
+/// A `serde_with` `SerializeAs`/`DeserializeAs` pair that (de)serializes [DateTime<Utc>] as a real
+/// RFC 3339 string.
+///
+/// `serde_with::Rfc3339` only implements this for `time::OffsetDateTime`, and chrono's own
+/// `Display` is not RFC 3339 (it renders e.g. `"2023-11-14 22:13:20 UTC"`, with a space separator
+/// and a `UTC` suffix instead of `T...Z`/`T...+00:00`), so neither can be used here directly.
+struct DateTimeRfc3339;
+
+impl SerializeAs<DateTime<Utc>> for DateTimeRfc3339 {
+    fn serialize_as<S>(value: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&value.to_rfc3339())
+    }
+}
+
+impl<'de> DeserializeAs<'de, DateTime<Utc>> for DateTimeRfc3339 {
+    fn deserialize_as<D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&s)
+            .map(|dt| dt.with_timezone(&Utc))
+            .map_err(serde::de::Error::custom)
+    }
 }
 
 /// Record metadata.
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RecordMeta {
     guid: String,
     #[serde(rename = "type")]
@@ -41,7 +81,112 @@ pub struct RecordMeta {
     source: String,
     seq: u32,
     version: u32,
-    timestamp: u32,
+    /// When this record was last written, serialized as RFC 3339 via [DateTimeRfc3339].
+    ///
+    /// Older documents stored this as a bare numeric epoch; [PickFirst] tries the RFC 3339 form
+    /// first and falls back to parsing a numeric timestamp so those documents keep deserializing.
+    #[serde_as(as = "PickFirst<(DateTimeRfc3339, TimestampSeconds<i64>)>")]
+    timestamp: DateTime<Utc>,
+}
+
+impl Default for RecordMeta {
+    fn default() -> Self {
+        Self {
+            guid: String::default(),
+            typ: String::default(),
+            id: String::default(),
+            source: String::default(),
+            seq: 0,
+            version: 0,
+            timestamp: DateTime::<Utc>::from(std::time::UNIX_EPOCH),
+        }
+    }
+}
+
+impl RecordMeta {
+    /// Get the version of this record. Incremented on every [TypedRecord::touch].
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Get the time this record was last written.
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+}
+
+/// A write was rejected because the document had been concurrently modified.
+///
+/// Returned by [ConflictPolicy::resolve] under [ConflictPolicy::Reject] when `expected_rev` does
+/// not match the revision actually stored.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("write conflict: expected revision {expected}, but the stored revision is {actual}")]
+pub struct ConflictError {
+    pub expected: String,
+    pub actual: String,
+}
+
+/// How to resolve a write conflict against a document that was concurrently modified.
+///
+/// Application-level `seq`/`version` on [RecordMeta] do not prevent two concurrent writers from
+/// clobbering each other; a CouchDB-backed persistence layer ties writes to the store's own MVCC
+/// `_rev` and, once it detects that the revision has moved since the record was read, asks
+/// [ConflictPolicy::resolve] what to do about it. That `_rev`-fetching/comparing plumbing lives in
+/// the `couch` persistence layer, which is not part of this snapshot; what's here is the
+/// resolution algorithm itself, independent of any particular store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Reject the write and return the conflict to the caller.
+    Reject,
+    /// Overwrite the stored document unconditionally.
+    LastWriteWins,
+    /// Merge the incoming body into the currently stored body via
+    /// [UntypedRecord::merge_json_value]'s underlying RFC 7386 merge: keys in the incoming body
+    /// replace those stored, and keys only in the stored body are kept. This is a two-way merge;
+    /// no common ancestor body is consulted.
+    Merge,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::Reject
+    }
+}
+
+impl ConflictPolicy {
+    /// Decide what should actually be written, given the revision the caller last read
+    /// (`expected_rev`), the revision and body actually stored right now (`actual_rev`, `stored`),
+    /// and the `incoming` body the caller wants to write.
+    ///
+    /// Returns the [Object] to write, or a [ConflictError] if [ConflictPolicy::Reject] applies and
+    /// the revisions don't match. If the revisions do match there is no conflict, and `incoming` is
+    /// returned as-is regardless of policy.
+    pub fn resolve(
+        self,
+        expected_rev: &str,
+        actual_rev: &str,
+        stored: &Object,
+        incoming: Object,
+    ) -> Result<Object, ConflictError> {
+        if expected_rev == actual_rev {
+            return Ok(incoming);
+        }
+        match self {
+            ConflictPolicy::Reject => Err(ConflictError {
+                expected: expected_rev.to_string(),
+                actual: actual_rev.to_string(),
+            }),
+            ConflictPolicy::LastWriteWins => Ok(incoming),
+            ConflictPolicy::Merge => {
+                let mut merged = Value::Object(stored.clone());
+                json_patch::merge(&mut merged, &Value::Object(incoming));
+                match merged {
+                    Value::Object(merged) => Ok(merged),
+                    _ => unreachable!("merging two JSON objects always yields an object"),
+                }
+            }
+        }
+    }
 }
 
 /// A trait to implement on value structs for typed [Record]s.
@@ -133,6 +278,47 @@ impl UntypedRecord {
         &self.meta.typ
     }
 
+    /// Decode this record into a type-erased [TypedRecord], looking up the decoder for
+    /// `self.typ()` in `registry`.
+    ///
+    /// This lets callers such as `post_record` persist any registered [TypedValue] without
+    /// matching on the type string themselves. Returns [DecodingError::UnknownType] if no type
+    /// was registered for `self.typ()` via [register_record_type].
+    pub fn into_typed_record_dyn(
+        self,
+        registry: &RecordTypeRegistry,
+    ) -> Result<Box<dyn ErasedRecord>, DecodingError> {
+        let typ = self.meta.typ.clone();
+        registry.decode(&typ, self)
+    }
+
+    /// Apply an RFC 6902 JSON Patch to this record's value, in place.
+    ///
+    /// Operations are applied in order. If any `test` operation fails, the whole patch is
+    /// aborted and the record is left unmodified, returning [DecodingError::Patch]: the record is
+    /// only overwritten once every operation, including `test`s, has succeeded.
+    pub fn apply_json_patch(&mut self, patch: json_patch::Patch) -> Result<(), DecodingError> {
+        let mut value = Value::Object(self.value.clone());
+        json_patch::patch(&mut value, &patch)?;
+        match value {
+            Value::Object(value) => {
+                self.value = value;
+                Ok(())
+            }
+            _ => Err(DecodingError::NotAnObject),
+        }
+    }
+
+    /// Stamp this record with the current time and bump its version. See [TypedRecord::touch].
+    ///
+    /// Only records decoded through [UntypedRecord::into_typed_record_dyn] are stamped by
+    /// [RecordTypeRegistry::decode], so callers that write an `UntypedRecord` directly, such as
+    /// `patch_record`, must call this themselves before persisting.
+    pub fn touch(&mut self) {
+        self.meta.timestamp = Utc::now();
+        self.meta.version = self.meta.version.wrapping_add(1);
+    }
+
     /// Merge this record's value with another JSON value.
     pub fn merge_json_value(
         &mut self,
@@ -152,6 +338,98 @@ impl UntypedRecord {
     }
 }
 
+/// A record whose body is kept as a single unparsed [RawValue] instead of being eagerly
+/// deserialized into an [Object].
+///
+/// [UntypedRecord] parses its body into a `serde_json::Map` on every read, which is wasted work
+/// on the hot `couch` -> `elastic` path where a record is only ever stored or forwarded verbatim.
+/// `RawRecord` keeps the body's original bytes untouched (preserving formatting and skipping the
+/// intermediate allocation) and only parses it, via [RawRecord::into_typed_record] or
+/// [RawRecord::into_untyped_record], once a caller actually needs a typed or structured view.
+///
+/// `serde_json::value::RawValue` cannot be combined with `#[serde(flatten)]`, so unlike
+/// `UntypedRecord` the wire shape here is an explicit `{ "$meta": ..., "value": ... }` envelope
+/// rather than a flattened object. `RawRecord` is meant for internal pass-through storage, not as
+/// a drop-in replacement for the public record JSON shape.
+///
+/// This type is the data representation for that redesign; wiring it into the `couch`/`elastic`
+/// read/write paths themselves is not part of this change.
+///
+/// Requires the `raw_value` feature.
+#[cfg(feature = "raw_value")]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RawRecord {
+    #[serde(rename = "$meta")]
+    pub meta: RecordMeta,
+    pub value: Box<RawValue>,
+}
+
+#[cfg(feature = "raw_value")]
+impl RawRecord {
+    /// Get the guid of the record.
+    pub fn guid(&self) -> &str {
+        &self.meta.guid
+    }
+
+    /// Get the type of the record.
+    pub fn typ(&self) -> &str {
+        &self.meta.typ
+    }
+
+    /// Parse the raw body into a typed [Record], only now allocating and validating its shape.
+    pub fn into_typed_record<T: TypedValue>(self) -> Result<TypedRecord<T>, DecodingError> {
+        if self.meta.typ.as_str() != T::NAME {
+            return Err(DecodingError::TypeMismatch(
+                T::NAME.to_string(),
+                self.meta.typ.clone(),
+            ));
+        }
+        let value: T = serde_json::from_str(self.value.get())?;
+        Ok(TypedRecord {
+            meta: self.meta,
+            value,
+        })
+    }
+
+    /// Parse the raw body into an [UntypedRecord], e.g. to merge or patch it before writing it
+    /// back out.
+    pub fn into_untyped_record(self) -> Result<UntypedRecord, DecodingError> {
+        let value: Value = serde_json::from_str(self.value.get())?;
+        match value {
+            Value::Object(value) => Ok(UntypedRecord {
+                meta: self.meta,
+                value,
+            }),
+            _ => Err(DecodingError::NotAnObject),
+        }
+    }
+}
+
+/// A borrowed view of a [RawRecord], for request handlers that only need to read through a record
+/// once (e.g. to forward its body verbatim into another store) without taking ownership of the
+/// request buffer.
+///
+/// Requires the `raw_value` feature.
+#[cfg(feature = "raw_value")]
+#[derive(Deserialize, Debug)]
+pub struct BorrowedRawRecord<'a> {
+    #[serde(rename = "$meta")]
+    pub meta: RecordMeta,
+    #[serde(borrow)]
+    pub value: &'a RawValue,
+}
+
+#[cfg(feature = "raw_value")]
+impl<'a> BorrowedRawRecord<'a> {
+    /// Copy the borrowed body into an owned [RawRecord].
+    pub fn into_owned(self) -> RawRecord {
+        RawRecord {
+            meta: self.meta,
+            value: self.value.to_owned(),
+        }
+    }
+}
+
 impl<T> TryFrom<UntypedRecord> for TypedRecord<T>
 where
     T: TypedValue,
@@ -205,6 +483,15 @@ where
         &self.meta.typ
     }
 
+    /// Stamp this record with the current time and bump its version.
+    ///
+    /// Call this on every write so consumers (e.g. incremental Elastic indexing) can rely on
+    /// `$meta.timestamp`/`$meta.version` for last-modified ordering.
+    pub fn touch(&mut self) {
+        self.meta.timestamp = Utc::now();
+        self.meta.version = self.meta.version.wrapping_add(1);
+    }
+
     /// Create a new record from an id and a value.
     pub fn from_id_and_value(id: impl ToString, value: T) -> Self {
         let id = id.to_string();
@@ -270,6 +557,252 @@ where
     }
 }
 
+/// A type-erased [TypedRecord], returned by decoders registered via [register_record_type].
+///
+/// This lets code that only knows a record's `typ()` string at runtime (e.g. the generic
+/// `post_record` route) still persist it through the normal `TypedRecord` machinery.
+pub trait ErasedRecord: fmt::Debug + Send + Sync {
+    /// Get the guid of the record.
+    fn guid(&self) -> &str;
+
+    /// Get the id of the record.
+    fn id(&self) -> &str;
+
+    /// Get the type of the record.
+    fn typ(&self) -> &str;
+
+    /// Convert the record back into an [UntypedRecord].
+    fn into_untyped_record(self: Box<Self>) -> Result<UntypedRecord, EncodingError>;
+
+    /// Stamp the record with the current time and bump its version. See [TypedRecord::touch].
+    fn touch(&mut self);
+}
+
+impl<T> ErasedRecord for TypedRecord<T>
+where
+    T: TypedValue + Send + Sync,
+{
+    fn guid(&self) -> &str {
+        TypedRecord::guid(self)
+    }
+
+    fn id(&self) -> &str {
+        TypedRecord::id(self)
+    }
+
+    fn typ(&self) -> &str {
+        TypedRecord::typ(self)
+    }
+
+    fn into_untyped_record(self: Box<Self>) -> Result<UntypedRecord, EncodingError> {
+        (*self).into_untyped_record()
+    }
+
+    fn touch(&mut self) {
+        TypedRecord::touch(self)
+    }
+}
+
+/// A decoder, keyed on [TypedValue::NAME], that turns an [UntypedRecord] into a type-erased
+/// [ErasedRecord].
+pub type DecodeFn = fn(UntypedRecord) -> Result<Box<dyn ErasedRecord>, DecodingError>;
+
+/// One entry in the [RecordTypeRegistry], collected via [inventory] from calls to
+/// [register_record_type].
+pub struct RecordTypeRegistration {
+    pub name: &'static str,
+    pub decode: DecodeFn,
+}
+
+inventory::collect!(RecordTypeRegistration);
+
+/// Register a [TypedValue] so [UntypedRecord::into_typed_record_dyn] can decode and persist it at
+/// runtime, without the router needing to know the concrete type.
+///
+/// Call this once per record type, typically next to the type's definition:
+///
+/// ```ignore
+/// oas_common::register_record_type!(Media);
+/// ```
+#[macro_export]
+macro_rules! register_record_type {
+    ($ty:ty) => {
+        inventory::submit! {
+            $crate::record::RecordTypeRegistration {
+                name: <$ty as $crate::record::TypedValue>::NAME,
+                decode: |record: $crate::record::UntypedRecord| {
+                    record
+                        .into_typed_record::<$ty>()
+                        .map(|record| Box::new(record) as Box<dyn $crate::record::ErasedRecord>)
+                },
+            }
+        }
+    };
+}
+
+/// The global registry of record types, folded once from all [RecordTypeRegistration] entries
+/// submitted via [register_record_type].
+pub struct RecordTypeRegistry(HashMap<&'static str, DecodeFn>);
+
+impl RecordTypeRegistry {
+    /// Access the process-wide registry, built on first use from all types registered via
+    /// [register_record_type].
+    pub fn global() -> &'static Self {
+        static REGISTRY: Lazy<RecordTypeRegistry> = Lazy::new(|| {
+            let map = inventory::iter::<RecordTypeRegistration>()
+                .map(|reg| (reg.name, reg.decode))
+                .collect();
+            RecordTypeRegistry(map)
+        });
+        &REGISTRY
+    }
+
+    /// Look up and run the decoder registered for `typ`, stamping the result with [ErasedRecord::touch]
+    /// so every write that goes through the registry gets a fresh `version`/`timestamp` regardless
+    /// of which handler called it.
+    pub fn decode(
+        &self,
+        typ: &str,
+        record: UntypedRecord,
+    ) -> Result<Box<dyn ErasedRecord>, DecodingError> {
+        let decode = self
+            .0
+            .get(typ)
+            .ok_or_else(|| DecodingError::UnknownType(typ.to_string()))?;
+        let mut record = decode(record)?;
+        record.touch();
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[cfg(feature = "raw_value")]
+    #[test]
+    fn raw_record_round_trips_through_untyped_record() {
+        let raw = r#"{"$meta":{"guid":"test_1","type":"test","id":"1","source":"","seq":0,"version":0,"timestamp":0},"value":{"count":1}}"#;
+        let record: RawRecord = serde_json::from_str(raw).unwrap();
+        assert_eq!(record.guid(), "test_1");
+        assert_eq!(record.typ(), "test");
+
+        let untyped = record.into_untyped_record().unwrap();
+        assert_eq!(untyped.value.get("count"), Some(&serde_json::json!(1)));
+    }
+
+    #[cfg(feature = "raw_value")]
+    #[test]
+    fn borrowed_raw_record_into_owned_preserves_the_body() {
+        let raw = r#"{"$meta":{"guid":"test_1","type":"test","id":"1","source":"","seq":0,"version":0,"timestamp":0},"value":{"count":1}}"#;
+        let borrowed: BorrowedRawRecord = serde_json::from_str(raw).unwrap();
+        let owned = borrowed.into_owned();
+        assert_eq!(owned.value.get(), r#"{"count":1}"#);
+    }
+
+    #[test]
+    fn apply_json_patch_aborts_without_writing_on_failed_test_op() {
+        let mut record =
+            UntypedRecord::with_typ_id_value("test", "1", serde_json::json!({"count": 1}))
+                .unwrap();
+        let patch: json_patch::Patch = serde_json::from_value(serde_json::json!([
+            { "op": "test", "path": "/count", "value": 2 },
+            { "op": "replace", "path": "/count", "value": 99 },
+        ]))
+        .unwrap();
+
+        let err = record.apply_json_patch(patch).unwrap_err();
+        assert!(matches!(err, DecodingError::Patch(_)));
+        assert_eq!(record.value.get("count"), Some(&serde_json::json!(1)));
+    }
+
+    #[test]
+    fn conflict_policy_resolve_matches_rev_and_short_circuits() {
+        let stored = serde_json::json!({"title": "stored"}).as_object().unwrap().clone();
+        let incoming = serde_json::json!({"title": "incoming"}).as_object().unwrap().clone();
+
+        // Matching revisions: no conflict, regardless of policy.
+        for policy in [
+            ConflictPolicy::Reject,
+            ConflictPolicy::LastWriteWins,
+            ConflictPolicy::Merge,
+        ] {
+            let resolved = policy
+                .resolve("rev-1", "rev-1", &stored, incoming.clone())
+                .unwrap();
+            assert_eq!(resolved, incoming);
+        }
+    }
+
+    #[test]
+    fn conflict_policy_reject_errors_on_stale_revision() {
+        let stored = serde_json::json!({"title": "stored"}).as_object().unwrap().clone();
+        let incoming = serde_json::json!({"title": "incoming"}).as_object().unwrap().clone();
+
+        let err = ConflictPolicy::Reject
+            .resolve("rev-1", "rev-2", &stored, incoming)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ConflictError {
+                expected: "rev-1".to_string(),
+                actual: "rev-2".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn conflict_policy_last_write_wins_overwrites_on_stale_revision() {
+        let stored = serde_json::json!({"title": "stored"}).as_object().unwrap().clone();
+        let incoming = serde_json::json!({"title": "incoming"}).as_object().unwrap().clone();
+
+        let resolved = ConflictPolicy::LastWriteWins
+            .resolve("rev-1", "rev-2", &stored, incoming.clone())
+            .unwrap();
+        assert_eq!(resolved, incoming);
+    }
+
+    #[test]
+    fn conflict_policy_merge_combines_stored_and_incoming_on_stale_revision() {
+        let stored = serde_json::json!({"title": "stored", "tags": ["a"]})
+            .as_object()
+            .unwrap()
+            .clone();
+        let incoming = serde_json::json!({"title": "incoming"}).as_object().unwrap().clone();
+
+        let resolved = ConflictPolicy::Merge
+            .resolve("rev-1", "rev-2", &stored, incoming)
+            .unwrap();
+        assert_eq!(resolved.get("title"), Some(&serde_json::json!("incoming")));
+        assert_eq!(resolved.get("tags"), Some(&serde_json::json!(["a"])));
+    }
+
+    #[test]
+    fn timestamp_round_trips_as_rfc3339_and_accepts_numeric_epoch() {
+        let mut meta = RecordMeta::default();
+        meta.timestamp = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+
+        let serialized = serde_json::to_value(&meta).unwrap();
+        let timestamp = serialized["timestamp"].as_str().expect("timestamp is a string");
+        assert!(
+            timestamp.contains('T'),
+            "expected RFC 3339 (with a 'T' date/time separator), got {timestamp:?}"
+        );
+
+        let round_tripped: RecordMeta = serde_json::from_value(serialized).unwrap();
+        assert_eq!(round_tripped.timestamp, meta.timestamp);
+
+        // Older documents stored `timestamp` as a bare numeric epoch; it must still deserialize.
+        let legacy = serde_json::json!({
+            "guid": "", "type": "", "id": "", "source": "", "seq": 0, "version": 0,
+            "timestamp": 1_700_000_000,
+        });
+        let from_numeric: RecordMeta = serde_json::from_value(legacy).unwrap();
+        assert_eq!(from_numeric.timestamp, meta.timestamp);
+    }
+}
+
 // impl<T> TypedRecord<T>
 // where
 //     T: TypedValue,
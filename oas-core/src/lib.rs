@@ -7,6 +7,13 @@ pub mod util;
 
 pub use oas_common::*;
 
+// Registers the built-in record types with the global `RecordTypeRegistry` (see
+// `oas_common::record::register_record_type`), so `post_record` can decode and persist them
+// without a hardcoded match. Without this, the registry is empty at startup and every `POST /`
+// for these types 4xxs with `DecodingError::UnknownType`.
+oas_common::register_record_type!(oas_common::types::Media);
+oas_common::register_record_type!(oas_common::types::Feed);
+
 pub struct State {
     pub db: couch::CouchDB,
     pub index: elastic::Index,
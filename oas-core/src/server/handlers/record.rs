@@ -1,14 +1,14 @@
-use oas_common::types::{Feed, Media};
-use oas_common::{Record, TypedValue, UntypedRecord};
+use oas_common::record::RecordTypeRegistry;
+use oas_common::{ConflictPolicy, UntypedRecord};
 use rocket::serde::json::Json;
-use rocket::{get, post, put, routes, Route};
+use rocket::{get, patch, post, put, routes, Route};
 use serde_json::Value;
 
 use crate::couch::Doc;
-use crate::server::error::{AppError, Result};
+use crate::server::error::Result;
 
 pub fn routes() -> Vec<Route> {
-    routes![get_record, post_record]
+    routes![get_record, post_record, patch_record]
 }
 
 #[get("/<guid>")]
@@ -25,18 +25,38 @@ async fn post_record(
 ) -> Result<serde_json::Value> {
     let db = &state.db;
 
-    let record = record.into_inner();
-    match record.typ() {
-        Media::NAME => {
-            let record = record.into_typed_record::<Media>()?;
-            db.put_record(record).await?;
-            Ok(Value::Bool(true).into())
-        }
-        Feed::NAME => {
-            let record = record.into_typed_record::<Feed>()?;
-            db.put_record(record).await?;
-            Ok(Value::Bool(true).into())
-        }
-        _ => Err(AppError::Other("Unknown type".to_string())),
-    }
+    let record = record
+        .into_inner()
+        .into_typed_record_dyn(RecordTypeRegistry::global())?;
+    // Reject rather than silently overwrite if the record changed since it was last read. The
+    // couch persistence layer fetches the stored `_rev` and runs it through
+    // `ConflictPolicy::resolve`; callers that want last-write-wins or a merge into the stored body can pass a
+    // different `ConflictPolicy` to `put_record_dyn_with` instead.
+    db.put_record_dyn_with(record, ConflictPolicy::Reject).await?;
+    Ok(Value::Bool(true).into())
+}
+
+/// Apply an RFC 6902 JSON Patch to a record.
+///
+/// Operations are applied in order; if any `test` operation fails, nothing is written and the
+/// error is surfaced to the caller, so a patch can be used as a compare-and-set style conditional
+/// update by prefixing it with a `test` op against the field the caller expects. That guard only
+/// covers the body the request itself asserts against, though: two concurrent patches can still
+/// both read the same base document and pass their own `test` ops against it. Writing through
+/// `put_untyped_record_with(.., ConflictPolicy::Reject)` closes that gap: the couch persistence
+/// layer runs the stored vs. expected `_rev` through `ConflictPolicy::resolve` and rejects the
+/// write if the record moved since this handler read it.
+#[patch("/<guid>", data = "<patch>")]
+async fn patch_record(
+    state: &rocket::State<crate::State>,
+    guid: String,
+    patch: Json<json_patch::Patch>,
+) -> Result<serde_json::Value> {
+    let db = &state.db;
+
+    let mut record = db.get_doc(&guid).await?.into_untyped_record()?;
+    record.apply_json_patch(patch.into_inner())?;
+    record.touch();
+    db.put_untyped_record_with(record, ConflictPolicy::Reject).await?;
+    Ok(Value::Bool(true).into())
 }